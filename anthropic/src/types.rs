@@ -1,9 +1,12 @@
 //! Module for types used in the API.
 use std::pin::Pin;
+use std::time::Duration;
 
+use base64::engine::general_purpose::STANDARD as BASE64_STANDARD;
+use base64::Engine;
 use derive_builder::Builder;
 use serde::{Deserialize, Serialize};
-use tokio_stream::Stream;
+use tokio_stream::{Stream, StreamExt};
 
 use crate::error::AnthropicError;
 use crate::DEFAULT_MODEL;
@@ -45,9 +48,177 @@ pub enum Role {
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum ContentBlock {
-    Text { text: String },
-    // TODO better types
-    Image { source: String, media_type: String, data: String },
+    Text {
+        text: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    Image {
+        source: ImageSource,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    /// A tool invocation emitted by the model.
+    ToolUse {
+        id: String,
+        name: String,
+        input: serde_json::Value,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+    /// The result of a tool invocation, sent back to the model.
+    ToolResult {
+        tool_use_id: String,
+        content: Vec<ContentBlock>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        is_error: Option<bool>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cache_control: Option<CacheControl>,
+    },
+}
+
+/// Marks a [ContentBlock] or the system prompt as eligible for prompt caching.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+pub struct CacheControl {
+    #[serde(rename = "type")]
+    pub cache_type: CacheType,
+}
+
+/// The kind of prompt cache entry to create.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheType {
+    Ephemeral,
+}
+
+/// The source of an image [ContentBlock].
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum ImageSource {
+    /// Image bytes, base64-encoded.
+    Base64 { media_type: MediaType, data: String },
+    /// A URL the API should fetch the image from.
+    Url { url: String },
+}
+
+/// The media type of an image sent to the API.
+#[derive(Copy, Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MediaType {
+    #[serde(rename = "image/jpeg")]
+    Jpeg,
+    #[serde(rename = "image/png")]
+    Png,
+    #[serde(rename = "image/gif")]
+    Gif,
+    #[serde(rename = "image/webp")]
+    Webp,
+}
+
+impl ContentBlock {
+    /// Builds an [ContentBlock::Image] from raw image bytes, base64-encoding them internally.
+    pub fn image_from_bytes(bytes: &[u8], media_type: MediaType) -> Self {
+        ContentBlock::Image {
+            source: ImageSource::Base64 { media_type, data: BASE64_STANDARD.encode(bytes) },
+            cache_control: None,
+        }
+    }
+
+    /// Builds an [ContentBlock::Image] by reading the file at `path` and sniffing its media type
+    /// from the file extension.
+    ///
+    /// Returns [AnthropicError::InvalidImage] if the file cannot be read or its extension is not
+    /// one of the supported image types.
+    pub fn image_from_path<P: AsRef<std::path::Path>>(path: P) -> Result<Self, AnthropicError> {
+        let path = path.as_ref();
+        let extension = path.extension().and_then(|ext| ext.to_str()).map(|ext| ext.to_ascii_lowercase());
+        let media_type = match extension.as_deref() {
+            Some("jpg") | Some("jpeg") => MediaType::Jpeg,
+            Some("png") => MediaType::Png,
+            Some("gif") => MediaType::Gif,
+            Some("webp") => MediaType::Webp,
+            _ => return Err(AnthropicError::InvalidImage(format!("unsupported image extension: {}", path.display()))),
+        };
+        let bytes = std::fs::read(path)
+            .map_err(|e| AnthropicError::InvalidImage(format!("failed to read {}: {e}", path.display())))?;
+        Ok(Self::image_from_bytes(&bytes, media_type))
+    }
+}
+
+impl From<&str> for ContentBlock {
+    fn from(text: &str) -> Self {
+        ContentBlock::Text { text: text.to_string(), cache_control: None }
+    }
+}
+
+impl From<String> for ContentBlock {
+    fn from(text: String) -> Self {
+        ContentBlock::Text { text, cache_control: None }
+    }
+}
+
+/// A tool made available to the model for use in a [MessagesRequest].
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+pub struct Tool {
+    /// The name of the tool.
+    pub name: String,
+    /// A description of what the tool does, used by the model to decide when and how to call it.
+    pub description: String,
+    /// A JSON Schema describing the shape of the tool's input.
+    pub input_schema: serde_json::Value,
+}
+
+/// Controls how (and whether) the model should use the provided [Tool]s.
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case", tag = "type")]
+pub enum ToolChoice {
+    /// The model decides whether to use a tool.
+    Auto,
+    /// The model must use one of the provided tools.
+    Any,
+    /// The model must use the named tool.
+    Tool { name: String },
+}
+
+/// The system prompt for a [MessagesRequest].
+///
+/// A plain string is the common case, but a large static system prompt can instead be split into
+/// [ContentBlock]s so that a prefix of it can be marked with [CacheControl].
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum System {
+    Text(String),
+    Blocks(Vec<ContentBlock>),
+}
+
+impl Default for System {
+    fn default() -> Self {
+        System::Text(String::new())
+    }
+}
+
+impl System {
+    fn is_empty(&self) -> bool {
+        matches!(self, System::Text(text) if text.is_empty())
+    }
+}
+
+impl From<String> for System {
+    fn from(text: String) -> Self {
+        System::Text(text)
+    }
+}
+
+impl From<&str> for System {
+    fn from(text: &str) -> Self {
+        System::Text(text.to_string())
+    }
+}
+
+impl From<Vec<ContentBlock>> for System {
+    fn from(blocks: Vec<ContentBlock>) -> Self {
+        System::Blocks(blocks)
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Builder, PartialEq, Eq)]
@@ -66,8 +237,8 @@ pub struct MessagesRequest {
     /// The User/Assistent prompts.
     pub messages: Vec<Message>,
     /// The System prompt.
-    #[serde(skip_serializing_if = "String::is_empty")]
-    pub system: String,
+    #[serde(skip_serializing_if = "System::is_empty")]
+    pub system: System,
     /// The model to use.
     #[builder(default = "DEFAULT_MODEL.to_string()")]
     pub model: String,
@@ -99,8 +270,72 @@ pub struct MessagesRequest {
     /// Recommended for advanced use cases only. You usually only need to use temperature.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub top_k: Option<usize>,
+    /// Tools the model may call while generating a response.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub tools: Vec<Tool>,
+    /// How the model should decide whether and which tool to use.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tool_choice: Option<ToolChoice>,
 }
 
+/// Ergonomically builds the strictly alternating user/assistant turns the Messages API requires.
+///
+/// Consecutive calls for the same role are merged into a single turn rather than rejected, so
+/// callers can push content incrementally without tracking the last role themselves. [build](Self::build)
+/// validates that the resulting turns start with `user` and alternate correctly.
+#[derive(Clone, Debug, Default)]
+pub struct Conversation {
+    system: System,
+    messages: Vec<Message>,
+}
+
+impl Conversation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the system prompt.
+    pub fn system(mut self, system: impl Into<System>) -> Self {
+        self.system = system.into();
+        self
+    }
+
+    /// Appends a user turn, merging into the previous turn if it was also a user turn.
+    pub fn user(self, content: impl Into<ContentBlock>) -> Self {
+        self.push(Role::User, content.into())
+    }
+
+    /// Appends an assistant turn, merging into the previous turn if it was also an assistant turn.
+    pub fn assistant(self, content: impl Into<ContentBlock>) -> Self {
+        self.push(Role::Assistant, content.into())
+    }
+
+    fn push(mut self, role: Role, block: ContentBlock) -> Self {
+        match self.messages.last_mut() {
+            Some(last) if last.role == role => last.content.push(block),
+            _ => self.messages.push(Message { role, content: vec![block] }),
+        }
+        self
+    }
+
+    /// Validates turn alternation and builds a [MessagesRequestBuilder] pre-populated with
+    /// `system` and `messages`.
+    ///
+    /// Returns [AnthropicError::InvalidConversation] if the turns don't start with `user`.
+    /// `push` already merges consecutive same-role turns as they're added, so once the first
+    /// turn is confirmed to be a user turn, strict alternation is guaranteed by construction.
+    pub fn build(self) -> Result<MessagesRequestBuilder, AnthropicError> {
+        if matches!(self.messages.first(), Some(first) if first.role != Role::User) {
+            return Err(AnthropicError::InvalidConversation(
+                "conversation must start with a user turn".to_string(),
+            ));
+        }
+        debug_assert!(self.messages.windows(2).all(|pair| pair[0].role != pair[1].role));
+        let mut builder = MessagesRequestBuilder::default();
+        builder.system(self.system).messages(self.messages);
+        Ok(builder)
+    }
+}
 
 #[derive(Clone, Serialize, Default, Debug, Builder, PartialEq)]
 #[builder(pattern = "mutable")]
@@ -120,6 +355,109 @@ pub struct TokenCountRequest {
     
 }
 
+/// Configures retry behavior for non-streaming `complete`/`messages` calls that hit
+/// [AnthropicError::RateLimited], [AnthropicError::Overloaded] or [AnthropicError::ServerError].
+///
+/// Retries use exponential backoff (doubling `initial_backoff` up to `max_backoff` on each
+/// attempt), honoring the response's `retry-after` header when present, and optionally jittered
+/// to avoid a thundering herd of retries across clients. Use [retry_with_backoff] to drive an
+/// actual request through this policy.
+#[derive(Clone, Default, Debug, Builder, PartialEq)]
+#[builder(pattern = "mutable")]
+#[builder(setter(into, strip_option), default)]
+#[builder(derive(Debug))]
+#[builder(build_fn(error = "AnthropicError"))]
+pub struct RetryConfig {
+    /// The maximum number of retry attempts before giving up and returning the error.
+    #[builder(default = "3")]
+    pub max_retries: usize,
+    /// The initial backoff delay, doubled after each failed attempt.
+    #[builder(default = "Duration::from_millis(500)")]
+    pub initial_backoff: Duration,
+    /// The maximum backoff delay, regardless of how many attempts have been made.
+    #[builder(default = "Duration::from_secs(30)")]
+    pub max_backoff: Duration,
+    /// Whether to randomize backoff delays to avoid clients retrying in lockstep.
+    #[builder(default = "true")]
+    pub jitter: bool,
+}
+
+impl RetryConfig {
+    /// Maps an HTTP response status into the [AnthropicError] retry kind it represents, or
+    /// `None` if `status` isn't one this policy retries.
+    pub fn classify_error(status: u16, retry_after: Option<Duration>) -> Option<AnthropicError> {
+        match status {
+            429 => Some(AnthropicError::RateLimited { retry_after }),
+            529 => Some(AnthropicError::Overloaded),
+            500..=599 => Some(AnthropicError::ServerError { status }),
+            _ => None,
+        }
+    }
+
+    /// Whether `error` is retryable under this policy and `attempt` (0-indexed) hasn't exhausted
+    /// `max_retries`.
+    pub fn should_retry(&self, error: &AnthropicError, attempt: usize) -> bool {
+        attempt < self.max_retries
+            && matches!(
+                error,
+                AnthropicError::RateLimited { .. } | AnthropicError::Overloaded | AnthropicError::ServerError { .. }
+            )
+    }
+
+    /// The delay to wait before retrying `attempt` (0-indexed), preferring a server-provided
+    /// `retry_after` over the computed exponential backoff, and applying jitter if configured.
+    pub fn backoff(&self, attempt: usize, retry_after: Option<Duration>) -> Duration {
+        if let Some(wait) = retry_after {
+            return wait.min(self.max_backoff);
+        }
+        let multiplier = 1u32.checked_shl(attempt as u32).unwrap_or(u32::MAX);
+        let delay = self.initial_backoff.saturating_mul(multiplier).min(self.max_backoff);
+        if self.jitter {
+            jittered(delay)
+        } else {
+            delay
+        }
+    }
+}
+
+/// Randomizes `delay` to somewhere in `[0.5, 1.5) * delay`, to avoid many clients retrying in
+/// lockstep after the same failure.
+fn jittered(delay: Duration) -> Duration {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.subsec_nanos()).unwrap_or(0);
+    let factor = 0.5 + (nanos % 1000) as f64 / 1000.0;
+    Duration::from_secs_f64(delay.as_secs_f64() * factor)
+}
+
+/// Parses a `retry-after` header value expressed in seconds. The HTTP-date form is not supported.
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    value.trim().parse::<u64>().ok().map(Duration::from_secs)
+}
+
+/// Drives `attempt` according to `config`, retrying with exponential backoff while the returned
+/// error is rate-limiting, overload, or a transient server error and attempts remain.
+pub async fn retry_with_backoff<F, Fut, T>(config: &RetryConfig, mut attempt: F) -> Result<T, AnthropicError>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = Result<T, AnthropicError>>,
+{
+    let mut attempts = 0;
+    loop {
+        match attempt().await {
+            Ok(value) => return Ok(value),
+            Err(err) if config.should_retry(&err, attempts) => {
+                let retry_after = match &err {
+                    AnthropicError::RateLimited { retry_after } => *retry_after,
+                    _ => None,
+                };
+                tokio::time::sleep(config.backoff(attempts, retry_after)).await;
+                attempts += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 /// Reason for stopping the response generation.
 #[derive(Copy, Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
@@ -130,6 +468,8 @@ pub enum StopReason {
     MaxTokens,
     /// One of the provided custom stop_sequences was generated.
     StopSequence,
+    /// The model invoked one or more tools and is waiting on their results.
+    ToolUse,
 }
 
 /// Billing and rate-limit usage.
@@ -140,6 +480,14 @@ pub struct Usage {
 
     /// The number of output tokens which were used.
     pub output_tokens: usize,
+
+    /// The number of input tokens used to create a new prompt cache entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_creation_input_tokens: Option<usize>,
+
+    /// The number of input tokens read from a prompt cache entry.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cache_read_input_tokens: Option<usize>,
 }
 
 
@@ -218,6 +566,11 @@ pub type MessagesResponseStream = Pin<Box<dyn Stream<Item = Result<MessagesStrea
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum ContentBlockDelta {
     TextDelta { text: String },
+    /// A fragment of a tool's `input` JSON, streamed incrementally.
+    ///
+    /// Fragments for a given content block index must be concatenated in order and parsed as
+    /// JSON once the corresponding `content_block_stop` event is received.
+    InputJsonDelta { partial_json: String },
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
@@ -247,7 +600,9 @@ pub struct MessageDelta {
 #[derive(Clone, Serialize, Deserialize, Debug, Eq, PartialEq)]
 #[serde(rename_all = "snake_case", tag = "type")]
 pub enum MessagesStreamEvent {
-    MessageStart { message: Message },
+    /// `message` has the same shape as a non-streaming [MessagesResponse], except `content` is
+    /// always empty and `stop_reason`/`stop_sequence` are always `None` at this point.
+    MessageStart { message: MessagesResponse },
     ContentBlockStart { index: usize, content_block: ContentBlock },
     ContentBlockDelta { index: usize, delta: ContentBlockDelta },
     ContentBlockStop { index: usize },
@@ -267,3 +622,594 @@ impl std::fmt::Display for StreamError {
         f.write_fmt(format_args!("Error ({}): {}", self.error_type, self.message))
     }
 }
+
+/// Incrementally folds [MessagesStreamEvent]s into a complete [MessagesResponse].
+///
+/// Content blocks may start, and their deltas may arrive, out of order with respect to other
+/// blocks' indices, so blocks are kept in a sparse vec indexed by their `index` rather than
+/// appended in arrival order.
+#[derive(Debug, Default)]
+pub struct StreamAccumulator {
+    id: String,
+    r#type: String,
+    model: String,
+    role: Role,
+    content: Vec<Option<ContentBlock>>,
+    partial_json: Vec<String>,
+    stop_reason: Option<StopReason>,
+    stop_sequence: Option<String>,
+    usage: Usage,
+}
+
+impl StreamAccumulator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Folds a single event into the accumulator.
+    pub fn push(&mut self, event: MessagesStreamEvent) -> Result<(), AnthropicError> {
+        match event {
+            MessagesStreamEvent::MessageStart { message } => {
+                self.id = message.id;
+                self.r#type = message.r#type;
+                self.model = message.model;
+                self.role = message.role;
+                self.usage = message.usage;
+            }
+            MessagesStreamEvent::ContentBlockStart { index, content_block } => {
+                if self.content.len() <= index {
+                    self.content.resize(index + 1, None);
+                    self.partial_json.resize(index + 1, String::new());
+                }
+                self.content[index] = Some(content_block);
+            }
+            MessagesStreamEvent::ContentBlockDelta { index, delta } => match delta {
+                ContentBlockDelta::TextDelta { text } => {
+                    if let Some(Some(ContentBlock::Text { text: existing, .. })) = self.content.get_mut(index) {
+                        existing.push_str(&text);
+                    }
+                }
+                ContentBlockDelta::InputJsonDelta { partial_json } => {
+                    if let Some(buf) = self.partial_json.get_mut(index) {
+                        buf.push_str(&partial_json);
+                    }
+                }
+            },
+            MessagesStreamEvent::ContentBlockStop { index } => {
+                if let Some(buf) = self.partial_json.get(index).filter(|buf| !buf.is_empty()) {
+                    let parsed: serde_json::Value =
+                        serde_json::from_str(buf).map_err(|e| AnthropicError::InvalidJson(e.to_string()))?;
+                    if let Some(Some(ContentBlock::ToolUse { input, .. })) = self.content.get_mut(index) {
+                        *input = parsed;
+                    }
+                }
+            }
+            MessagesStreamEvent::MessageDelta { delta, usage } => {
+                self.stop_reason = delta.stop_reason;
+                self.stop_sequence = delta.stop_sequence;
+                // `message_delta`'s usage is cumulative, not incremental, so it replaces rather
+                // than adds to the placeholder reported in `message_start`.
+                self.usage.output_tokens = usage.output_tokens;
+            }
+            MessagesStreamEvent::MessageStop => {}
+        }
+        Ok(())
+    }
+
+    /// Materializes the response as accumulated so far. Safe to call before the stream has
+    /// finished, e.g. to recover a partial result after a stream error.
+    pub fn finish(self) -> MessagesResponse {
+        MessagesResponse {
+            id: self.id,
+            r#type: self.r#type,
+            role: self.role,
+            content: self.content.into_iter().flatten().collect(),
+            model: self.model,
+            stop_reason: self.stop_reason,
+            stop_sequence: self.stop_sequence,
+            usage: self.usage,
+        }
+    }
+}
+
+/// The partial result recovered when a [MessagesResponseStream] errors before completing.
+#[derive(Debug)]
+pub struct AggregateError {
+    pub source: AnthropicError,
+    pub partial: MessagesResponse,
+}
+
+impl std::fmt::Display for AggregateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "stream aggregation failed: {}", self.source)
+    }
+}
+
+impl std::error::Error for AggregateError {}
+
+/// Adapts a [MessagesResponseStream] into a future yielding the complete [MessagesResponse].
+pub trait MessagesStreamExt {
+    fn aggregate(self) -> Pin<Box<dyn std::future::Future<Output = Result<MessagesResponse, AggregateError>> + Send>>;
+}
+
+impl MessagesStreamExt for MessagesResponseStream {
+    fn aggregate(mut self) -> Pin<Box<dyn std::future::Future<Output = Result<MessagesResponse, AggregateError>> + Send>> {
+        Box::pin(async move {
+            let mut acc = StreamAccumulator::new();
+            while let Some(item) = self.next().await {
+                let event = match item {
+                    Ok(event) => event,
+                    Err(source) => return Err(AggregateError { source, partial: acc.finish() }),
+                };
+                let is_stop = matches!(event, MessagesStreamEvent::MessageStop);
+                if let Err(source) = acc.push(event) {
+                    return Err(AggregateError { source, partial: acc.finish() });
+                }
+                if is_stop {
+                    break;
+                }
+            }
+            Ok(acc.finish())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn image_from_bytes_base64_encodes_and_serializes() {
+        let block = ContentBlock::image_from_bytes(b"hi", MediaType::Png);
+        assert_eq!(
+            block,
+            ContentBlock::Image {
+                source: ImageSource::Base64 { media_type: MediaType::Png, data: "aGk=".to_string() },
+                cache_control: None,
+            }
+        );
+        let json = serde_json::to_value(&block).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "image",
+                "source": {"type": "base64", "media_type": "image/png", "data": "aGk="},
+            })
+        );
+    }
+
+    #[test]
+    fn image_from_path_matches_extension_case_insensitively() {
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("anthropic-rs-test-{}.JPG", std::process::id()));
+        std::fs::write(&path, b"hi").unwrap();
+        let block = ContentBlock::image_from_path(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            block,
+            ContentBlock::Image {
+                source: ImageSource::Base64 { media_type: MediaType::Jpeg, data: "aGk=".to_string() },
+                cache_control: None,
+            }
+        );
+    }
+
+    #[test]
+    fn system_text_is_skipped_when_empty() {
+        let request = MessagesRequestBuilder::default()
+            .messages(vec![])
+            .max_tokens(16usize)
+            .build()
+            .unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("system").is_none());
+    }
+
+    #[test]
+    fn system_blocks_round_trip() {
+        let system = System::Blocks(vec![ContentBlock::Text {
+            text: "you are a helpful assistant".to_string(),
+            cache_control: Some(CacheControl { cache_type: CacheType::Ephemeral }),
+        }]);
+        let json = serde_json::to_value(&system).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([{
+                "type": "text",
+                "text": "you are a helpful assistant",
+                "cache_control": {"type": "ephemeral"},
+            }])
+        );
+        let parsed: System = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, system);
+    }
+
+    #[test]
+    fn cache_control_omitted_when_none() {
+        let block = ContentBlock::Text { text: "hi".to_string(), cache_control: None };
+        let json = serde_json::to_value(&block).unwrap();
+        assert!(json.get("cache_control").is_none());
+    }
+
+    #[test]
+    fn tool_round_trips() {
+        let tool = Tool {
+            name: "get_weather".to_string(),
+            description: "Gets the current weather for a location".to_string(),
+            input_schema: serde_json::json!({
+                "type": "object",
+                "properties": {"location": {"type": "string"}},
+                "required": ["location"],
+            }),
+        };
+        let json = serde_json::to_value(&tool).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "name": "get_weather",
+                "description": "Gets the current weather for a location",
+                "input_schema": {
+                    "type": "object",
+                    "properties": {"location": {"type": "string"}},
+                    "required": ["location"],
+                },
+            })
+        );
+        let parsed: Tool = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, tool);
+    }
+
+    #[test]
+    fn tool_choice_variants_round_trip() {
+        let cases = [
+            (ToolChoice::Auto, serde_json::json!({"type": "auto"})),
+            (ToolChoice::Any, serde_json::json!({"type": "any"})),
+            (ToolChoice::Tool { name: "get_weather".to_string() }, serde_json::json!({"type": "tool", "name": "get_weather"})),
+        ];
+        for (choice, expected) in cases {
+            let json = serde_json::to_value(&choice).unwrap();
+            assert_eq!(json, expected);
+            let parsed: ToolChoice = serde_json::from_value(json).unwrap();
+            assert_eq!(parsed, choice);
+        }
+    }
+
+    #[test]
+    fn content_block_tool_use_round_trips() {
+        let block = ContentBlock::ToolUse {
+            id: "tool_1".to_string(),
+            name: "get_weather".to_string(),
+            input: serde_json::json!({"location": "NYC"}),
+            cache_control: None,
+        };
+        let json = serde_json::to_value(&block).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "tool_use",
+                "id": "tool_1",
+                "name": "get_weather",
+                "input": {"location": "NYC"},
+            })
+        );
+        let parsed: ContentBlock = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, block);
+    }
+
+    #[test]
+    fn content_block_tool_result_round_trips() {
+        let block = ContentBlock::ToolResult {
+            tool_use_id: "tool_1".to_string(),
+            content: vec![ContentBlock::Text { text: "72F and sunny".to_string(), cache_control: None }],
+            is_error: Some(false),
+            cache_control: None,
+        };
+        let json = serde_json::to_value(&block).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "type": "tool_result",
+                "tool_use_id": "tool_1",
+                "content": [{"type": "text", "text": "72F and sunny"}],
+                "is_error": false,
+            })
+        );
+        let parsed: ContentBlock = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed, block);
+    }
+
+    #[test]
+    fn tools_and_tool_choice_omitted_when_empty() {
+        let request = MessagesRequestBuilder::default()
+            .messages(vec![])
+            .max_tokens(16usize)
+            .build()
+            .unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert!(json.get("tools").is_none());
+        assert!(json.get("tool_choice").is_none());
+    }
+
+    #[test]
+    fn tools_and_tool_choice_serialize_when_set() {
+        let tool = Tool {
+            name: "get_weather".to_string(),
+            description: "Gets the current weather for a location".to_string(),
+            input_schema: serde_json::json!({"type": "object"}),
+        };
+        let request = MessagesRequestBuilder::default()
+            .messages(vec![])
+            .max_tokens(16usize)
+            .tools(vec![tool])
+            .tool_choice(ToolChoice::Auto)
+            .build()
+            .unwrap();
+        let json = serde_json::to_value(&request).unwrap();
+        assert_eq!(json["tools"][0]["name"], "get_weather");
+        assert_eq!(json["tool_choice"], serde_json::json!({"type": "auto"}));
+    }
+
+    fn message_start(usage: Usage) -> MessagesStreamEvent {
+        MessagesStreamEvent::MessageStart {
+            message: MessagesResponse {
+                id: "msg_1".to_string(),
+                r#type: "message".to_string(),
+                role: Role::Assistant,
+                content: vec![],
+                model: "claude-3".to_string(),
+                stop_reason: None,
+                stop_sequence: None,
+                usage,
+            },
+        }
+    }
+
+    #[test]
+    fn usage_output_tokens_is_overwritten_not_accumulated_by_message_delta() {
+        let mut acc = StreamAccumulator::new();
+        acc.push(message_start(Usage {
+            input_tokens: 10,
+            output_tokens: 1,
+            cache_creation_input_tokens: None,
+            cache_read_input_tokens: None,
+        }))
+        .unwrap();
+        acc.push(MessagesStreamEvent::MessageDelta {
+            delta: MessageDelta { stop_reason: Some(StopReason::EndTurn), stop_sequence: None },
+            usage: MessageDeltaUsage { output_tokens: 15 },
+        })
+        .unwrap();
+
+        let response = acc.finish();
+        assert_eq!(response.usage.output_tokens, 15);
+        assert_eq!(response.usage.input_tokens, 10);
+    }
+
+    #[test]
+    fn content_blocks_assemble_out_of_order() {
+        let mut acc = StreamAccumulator::new();
+        acc.push(MessagesStreamEvent::ContentBlockStart {
+            index: 1,
+            content_block: ContentBlock::Text { text: String::new(), cache_control: None },
+        })
+        .unwrap();
+        acc.push(MessagesStreamEvent::ContentBlockStart {
+            index: 0,
+            content_block: ContentBlock::Text { text: String::new(), cache_control: None },
+        })
+        .unwrap();
+        acc.push(MessagesStreamEvent::ContentBlockDelta {
+            index: 0,
+            delta: ContentBlockDelta::TextDelta { text: "hello".to_string() },
+        })
+        .unwrap();
+        acc.push(MessagesStreamEvent::ContentBlockDelta {
+            index: 1,
+            delta: ContentBlockDelta::TextDelta { text: "world".to_string() },
+        })
+        .unwrap();
+
+        let response = acc.finish();
+        assert_eq!(
+            response.content,
+            vec![
+                ContentBlock::Text { text: "hello".to_string(), cache_control: None },
+                ContentBlock::Text { text: "world".to_string(), cache_control: None },
+            ]
+        );
+    }
+
+    #[test]
+    fn tool_use_input_json_assembles_from_partial_fragments() {
+        let mut acc = StreamAccumulator::new();
+        acc.push(MessagesStreamEvent::ContentBlockStart {
+            index: 0,
+            content_block: ContentBlock::ToolUse {
+                id: "tool_1".to_string(),
+                name: "get_weather".to_string(),
+                input: serde_json::Value::Null,
+                cache_control: None,
+            },
+        })
+        .unwrap();
+        acc.push(MessagesStreamEvent::ContentBlockDelta {
+            index: 0,
+            delta: ContentBlockDelta::InputJsonDelta { partial_json: "{\"loc".to_string() },
+        })
+        .unwrap();
+        acc.push(MessagesStreamEvent::ContentBlockDelta {
+            index: 0,
+            delta: ContentBlockDelta::InputJsonDelta { partial_json: "ation\":\"NYC\"}".to_string() },
+        })
+        .unwrap();
+        acc.push(MessagesStreamEvent::ContentBlockStop { index: 0 }).unwrap();
+
+        let response = acc.finish();
+        match &response.content[0] {
+            ContentBlock::ToolUse { input, .. } => assert_eq!(input, &serde_json::json!({"location": "NYC"})),
+            other => panic!("unexpected content block: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn empty_final_text_block_is_valid() {
+        let mut acc = StreamAccumulator::new();
+        acc.push(MessagesStreamEvent::ContentBlockStart {
+            index: 0,
+            content_block: ContentBlock::Text { text: String::new(), cache_control: None },
+        })
+        .unwrap();
+        acc.push(MessagesStreamEvent::ContentBlockStop { index: 0 }).unwrap();
+
+        let response = acc.finish();
+        assert_eq!(response.content, vec![ContentBlock::Text { text: String::new(), cache_control: None }]);
+    }
+
+    #[tokio::test]
+    async fn aggregate_surfaces_partial_result_on_stream_error() {
+        let events: Vec<Result<MessagesStreamEvent, AnthropicError>> = vec![
+            Ok(message_start(Usage {
+                input_tokens: 5,
+                output_tokens: 0,
+                cache_creation_input_tokens: None,
+                cache_read_input_tokens: None,
+            })),
+            Ok(MessagesStreamEvent::ContentBlockStart {
+                index: 0,
+                content_block: ContentBlock::Text { text: String::new(), cache_control: None },
+            }),
+            Ok(MessagesStreamEvent::ContentBlockDelta {
+                index: 0,
+                delta: ContentBlockDelta::TextDelta { text: "partial".to_string() },
+            }),
+            Err(AnthropicError::InvalidJson("boom".to_string())),
+        ];
+        let stream: MessagesResponseStream = Box::pin(tokio_stream::iter(events));
+
+        let err = stream.aggregate().await.unwrap_err();
+
+        assert_eq!(err.partial.id, "msg_1");
+        assert_eq!(
+            err.partial.content,
+            vec![ContentBlock::Text { text: "partial".to_string(), cache_control: None }]
+        );
+    }
+
+    #[test]
+    fn classify_error_maps_retryable_statuses() {
+        assert_eq!(RetryConfig::classify_error(429, None), Some(AnthropicError::RateLimited { retry_after: None }));
+        assert_eq!(RetryConfig::classify_error(529, None), Some(AnthropicError::Overloaded));
+        assert_eq!(RetryConfig::classify_error(503, None), Some(AnthropicError::ServerError { status: 503 }));
+        assert_eq!(RetryConfig::classify_error(404, None), None);
+    }
+
+    #[test]
+    fn should_retry_respects_max_retries() {
+        let config = RetryConfigBuilder::default().max_retries(2usize).build().unwrap();
+        let err = AnthropicError::Overloaded;
+        assert!(config.should_retry(&err, 0));
+        assert!(config.should_retry(&err, 1));
+        assert!(!config.should_retry(&err, 2));
+        assert!(!config.should_retry(&AnthropicError::InvalidJson("x".to_string()), 0));
+    }
+
+    #[test]
+    fn backoff_doubles_and_caps_at_max() {
+        let config = RetryConfigBuilder::default()
+            .initial_backoff(Duration::from_millis(100))
+            .max_backoff(Duration::from_millis(300))
+            .jitter(false)
+            .build()
+            .unwrap();
+        assert_eq!(config.backoff(0, None), Duration::from_millis(100));
+        assert_eq!(config.backoff(1, None), Duration::from_millis(200));
+        assert_eq!(config.backoff(2, None), Duration::from_millis(300));
+        assert_eq!(config.backoff(0, Some(Duration::from_millis(50))), Duration::from_millis(50));
+    }
+
+    #[test]
+    fn parse_retry_after_parses_seconds() {
+        assert_eq!(parse_retry_after("5"), Some(Duration::from_secs(5)));
+        assert_eq!(parse_retry_after("not-a-number"), None);
+    }
+
+    #[tokio::test]
+    async fn retry_with_backoff_retries_then_succeeds() {
+        let config = RetryConfigBuilder::default()
+            .max_retries(3usize)
+            .initial_backoff(Duration::from_millis(1))
+            .jitter(false)
+            .build()
+            .unwrap();
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let result = retry_with_backoff(&config, || {
+            let n = attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                if n < 2 {
+                    Err(AnthropicError::Overloaded)
+                } else {
+                    Ok("ok")
+                }
+            }
+        })
+        .await;
+        assert_eq!(result, Ok("ok"));
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn retry_with_backoff_gives_up_after_max_retries() {
+        let config = RetryConfigBuilder::default()
+            .max_retries(1usize)
+            .initial_backoff(Duration::from_millis(1))
+            .jitter(false)
+            .build()
+            .unwrap();
+        let attempts = std::sync::atomic::AtomicUsize::new(0);
+        let result: Result<(), AnthropicError> = retry_with_backoff(&config, || {
+            attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async { Err(AnthropicError::Overloaded) }
+        })
+        .await;
+        assert_eq!(result, Err(AnthropicError::Overloaded));
+        assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn conversation_merges_consecutive_same_role_turns() {
+        let conversation = Conversation::new().user("hi").user("and also this");
+
+        assert_eq!(
+            conversation.messages,
+            vec![Message {
+                role: Role::User,
+                content: vec![ContentBlock::from("hi"), ContentBlock::from("and also this")],
+            }]
+        );
+    }
+
+    #[test]
+    fn conversation_build_rejects_assistant_first_turn() {
+        let result = Conversation::new().assistant("hi").build();
+        assert_eq!(
+            result.unwrap_err(),
+            AnthropicError::InvalidConversation("conversation must start with a user turn".to_string())
+        );
+    }
+
+    #[test]
+    fn conversation_build_produces_alternating_messages() {
+        let builder =
+            Conversation::new().user("hello").assistant("hi there").user("how are you").build().unwrap();
+        let request = builder.max_tokens(64usize).build().unwrap();
+
+        assert_eq!(
+            request.messages,
+            vec![
+                Message { role: Role::User, content: vec![ContentBlock::from("hello")] },
+                Message { role: Role::Assistant, content: vec![ContentBlock::from("hi there")] },
+                Message { role: Role::User, content: vec![ContentBlock::from("how are you")] },
+            ]
+        );
+    }
+}